@@ -1,4 +1,7 @@
-use std::io::{Read, self};
+use std::fmt;
+use std::io::{Read, Write, self};
+
+pub mod program;
 
 pub fn ecb_calc(row: &[u8]) -> u8 {
     row.iter().map(|b| *b as u16).reduce(|acc, b| {
@@ -8,7 +11,7 @@ pub fn ecb_calc(row: &[u8]) -> u8 {
     }).unwrap() as u8
 }
 
-#[derive(Clone, Copy)]
+#[derive(Clone, Copy, Debug)]
 pub enum Device {
     Mpa1016,
     Mpa1036,
@@ -31,6 +34,15 @@ impl Device {
         }
     }
 
+    pub const fn jtag_id(self) -> u32 {
+        match self {
+            Self::Mpa1016 => 0x1390E01D,
+            Self::Mpa1036 => 0x1391E01D,
+            Self::Mpa1064 => 0x1393401D,
+            Self::Mpa1100 => 0x1392001D,
+        }
+    }
+
     pub const fn rows(self) -> usize {
         match self {
             Self::Mpa1016 => 95,
@@ -50,61 +62,341 @@ impl Device {
     }
 }
 
-struct Bitstream {
-    device: Device,
+/// An in-memory model of a device's configuration fabric: one row of bytes
+/// per configuration row, excluding the trailing error-check byte (which is
+/// recomputed on demand from the row contents rather than stored).
+#[derive(Clone, Debug)]
+pub(crate) struct Fabric {
+    pub(crate) rows: Vec<Vec<u8>>,
+}
+
+impl Fabric {
+    fn new(device: Device) -> Self {
+        Self {
+            rows: vec![vec![0; device.bytes_per_row() - 1]; device.rows()],
+        }
+    }
+
+    fn get(&self, row: usize, column: usize, bit: u8) -> bool {
+        (self.rows[row][column] & (1 << bit)) != 0
+    }
+
+    fn set(&mut self, row: usize, column: usize, bit: u8, value: bool) {
+        if value {
+            self.rows[row][column] |= 1 << bit;
+        } else {
+            self.rows[row][column] &= !(1 << bit);
+        }
+    }
+}
+
+/// Everything that can go wrong while parsing a `.bit` stream.
+#[derive(Debug)]
+pub enum BitstreamError {
+    /// The JTAG IDCODE at the start of the stream didn't match any known device.
+    UnknownIdcode(u32),
+    /// The data-type byte requested a mode this crate doesn't (yet) decode.
+    UnsupportedMode { test: bool, encrypted: bool, compressed: bool },
+    /// A row's trailing error-check byte didn't match the computed checksum.
+    EcbMismatch { row: usize, expected: u8, got: u8 },
+    /// The stream ended before a complete bitstream could be read.
+    Truncated,
+    /// An underlying I/O error occurred while reading the stream.
+    Io(io::Error),
+    /// A fuse-map line or header didn't match the expected `row:col:bit` or
+    /// `idcode:XXXXXXXX` syntax.
+    MalformedFuseMap(String),
+    /// One of the data-type byte's must-be-zero bits (3-7) was set.
+    ReservedBitsSet(u8),
+}
+
+impl fmt::Display for BitstreamError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::UnknownIdcode(idcode) => write!(f, "unrecognised JTAG IDCODE {idcode:08x}"),
+            Self::UnsupportedMode { test, encrypted, compressed } => write!(
+                f,
+                "unsupported data-type mode (test: {test}, encrypted: {encrypted}, compressed: {compressed})"
+            ),
+            Self::EcbMismatch { row, expected, got } => write!(
+                f,
+                "ECB checksum mismatch for row {row}: expected {expected:#04x}, got {got:#04x}"
+            ),
+            Self::Truncated => write!(f, "stream ended before a complete bitstream could be read"),
+            Self::Io(err) => write!(f, "I/O error: {err}"),
+            Self::MalformedFuseMap(line) => write!(f, "malformed fuse-map line: {line:?}"),
+            Self::ReservedBitsSet(data_type) => write!(f, "must-be-zero section not zero in data-type byte {data_type:#04x}"),
+        }
+    }
+}
+
+impl std::error::Error for BitstreamError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Self::Io(err) => Some(err),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for BitstreamError {
+    fn from(err: io::Error) -> Self {
+        if err.kind() == io::ErrorKind::UnexpectedEof {
+            Self::Truncated
+        } else {
+            Self::Io(err)
+        }
+    }
+}
+
+/// Decodes a single raw configuration row (still including its trailing ECB
+/// byte) into the plain bytes the rest of the pipeline checksums and stores.
+pub trait RowCodec {
+    fn decode_row(&mut self, raw: &[u8]) -> Vec<u8>;
+}
+
+/// The codec for plain, uncompressed rows: bytes pass through unchanged.
+/// This is the only mode the original format supported.
+#[derive(Default)]
+struct IdentityCodec;
+
+impl RowCodec for IdentityCodec {
+    fn decode_row(&mut self, raw: &[u8]) -> Vec<u8> {
+        raw.to_vec()
+    }
+}
+
+/// Placeholder codec for the test-data mode. The on-disk test-data layout
+/// isn't documented yet, so this just passes rows through unchanged; swap
+/// in a real decoder via [`CodecRegistry::with_test_codec`] once the format
+/// is known.
+#[derive(Default)]
+pub struct TestDataCodec;
+
+impl RowCodec for TestDataCodec {
+    fn decode_row(&mut self, raw: &[u8]) -> Vec<u8> {
+        raw.to_vec()
+    }
+}
+
+/// Placeholder codec for the compressed mode. No decompression is
+/// implemented yet, so this just passes rows through unchanged; swap in a
+/// real decoder via [`CodecRegistry::with_compressed_codec`] once the
+/// compression scheme is known.
+#[derive(Default)]
+pub struct CompressedCodec;
+
+impl RowCodec for CompressedCodec {
+    fn decode_row(&mut self, raw: &[u8]) -> Vec<u8> {
+        raw.to_vec()
+    }
+}
+
+/// Placeholder codec for the encrypted mode. No decryption is implemented
+/// yet, so this just passes rows through unchanged; swap in a real decoder
+/// via [`CodecRegistry::with_encrypted_codec`] once the cipher is known.
+#[derive(Default)]
+pub struct EncryptedCodec;
+
+impl RowCodec for EncryptedCodec {
+    fn decode_row(&mut self, raw: &[u8]) -> Vec<u8> {
+        raw.to_vec()
+    }
+}
+
+/// Selects which [`RowCodec`] handles a row based on the data-type byte's
+/// mode bits. The test-data, compressed and encrypted modes have no built-in
+/// decoder; register one with [`Self::with_test_codec`] and friends to parse
+/// streams using them.
+#[derive(Default)]
+pub struct CodecRegistry {
+    test: Option<Box<dyn RowCodec>>,
+    compressed: Option<Box<dyn RowCodec>>,
+    encrypted: Option<Box<dyn RowCodec>>,
+}
+
+impl CodecRegistry {
+    pub fn with_test_codec(mut self, codec: Box<dyn RowCodec>) -> Self {
+        self.test = Some(codec);
+        self
+    }
+
+    pub fn with_compressed_codec(mut self, codec: Box<dyn RowCodec>) -> Self {
+        self.compressed = Some(codec);
+        self
+    }
+
+    pub fn with_encrypted_codec(mut self, codec: Box<dyn RowCodec>) -> Self {
+        self.encrypted = Some(codec);
+        self
+    }
+
+    fn codec_for(&mut self, data_type: u8) -> Result<&mut (dyn RowCodec + 'static), BitstreamError> {
+        if data_type & 0x1 != 0 {
+            return self.test.as_deref_mut().ok_or(BitstreamError::UnsupportedMode {
+                test: true,
+                encrypted: data_type & 0x2 != 0,
+                compressed: data_type & 0x4 != 0,
+            });
+        }
+
+        if data_type & 0x4 != 0 {
+            return self.compressed.as_deref_mut().ok_or(BitstreamError::UnsupportedMode {
+                test: false,
+                encrypted: data_type & 0x2 != 0,
+                compressed: true,
+            });
+        }
+
+        self.encrypted.as_deref_mut().ok_or(BitstreamError::UnsupportedMode {
+            test: false,
+            encrypted: true,
+            compressed: false,
+        })
+    }
+}
+
+#[derive(Debug)]
+pub struct Bitstream {
+    pub(crate) device: Device,
     data_type: u8,
+    pub(crate) fabric: Fabric,
 }
 
 impl Bitstream {
-    pub fn new<R: Read>(mut input: R) -> io::Result<Self> {
+    pub fn new<R: Read>(input: R) -> Result<Self, BitstreamError> {
+        Self::new_with_codecs(input, CodecRegistry::default())
+    }
+
+    /// Like [`Self::new`], but with caller-supplied decoders for the
+    /// test-data, compressed and encrypted data-type modes.
+    pub fn new_with_codecs<R: Read>(mut input: R, mut registry: CodecRegistry) -> Result<Self, BitstreamError> {
         // Bytes 0-3: JTAG ID (big-endian)
         let mut jtag_id = [0; 4];
         input.read_exact(&mut jtag_id)?;
 
         let idcode = u32::from_be_bytes(jtag_id);
-        let device = if let Some(device) = Device::try_from_jtag(idcode) {
-            device
-        } else {
-            panic!("Unrecognised JTAG IDCODE {idcode:08x}");
-        };
+        let device = Device::try_from_jtag(idcode).ok_or(BitstreamError::UnknownIdcode(idcode))?;
 
         // Byte 4: data type
         let mut data_type = [0; 1];
         input.read_exact(&mut data_type)?;
         let data_type = data_type[0];
 
-        assert_eq!(data_type & 0x1, 0, "test data mode not yet implemented");
-        assert_eq!(data_type & 0x2, 0, "encrypted data mode unsupported");
-        assert_eq!(data_type & 0x4, 0, "compressed data mode unsupported");
-        assert_eq!(data_type & 0xF8, 0, "must-be-zero section not zero");
+        if data_type & 0xF8 != 0 {
+            return Err(BitstreamError::ReservedBitsSet(data_type));
+        }
+
+        let mut identity = IdentityCodec;
+        let mut fabric = Fabric::new(device);
 
         // for each row:
         for row_index in 0..device.rows() {
-            let mut row = vec![0; device.bytes_per_row()];
-            input.read_exact(&mut row)?;
-
-            for column_index in 0..(device.bytes_per_row()-1) {
-                for bit in 0..8 {
-                    if (row[column_index] & (1 << bit)) != 0 {
-                        println!("{row_index}:{column_index}:{bit}");
-                    }
-                }
-            }
+            let mut raw_row = vec![0; device.bytes_per_row()];
+            input.read_exact(&mut raw_row)?;
+
+            let codec: &mut dyn RowCodec = if data_type & 0x7 == 0 {
+                &mut identity
+            } else {
+                registry.codec_for(data_type)?
+            };
+            let row = codec.decode_row(&raw_row);
 
             // Last byte: error check byte
-            assert_eq!(ecb_calc(&row[0..device.bytes_per_row()-1]), row[device.bytes_per_row()-1], "ECB checksum mismatch for row {row_index}");
+            let expected = ecb_calc(&row[0..device.bytes_per_row()-1]);
+            let got = row[device.bytes_per_row()-1];
+            if expected != got {
+                return Err(BitstreamError::EcbMismatch { row: row_index, expected, got });
+            }
+
+            fabric.rows[row_index] = row[0..device.bytes_per_row()-1].to_vec();
         }
 
         Ok(Self {
             device,
             data_type,
+            fabric,
+        })
+    }
+
+    /// Serialize this bitstream back into the on-disk `.bit` format: the
+    /// big-endian JTAG IDCODE, the data-type byte, then each row's packed
+    /// fuse data followed by a freshly computed error-check byte.
+    pub fn write<W: Write>(&self, mut out: W) -> io::Result<()> {
+        out.write_all(&self.device.jtag_id().to_be_bytes())?;
+        out.write_all(&[self.data_type])?;
+
+        for row in &self.fabric.rows {
+            out.write_all(row)?;
+            out.write_all(&[ecb_calc(row)])?;
+        }
+
+        Ok(())
+    }
+
+    /// Iterate over every set fuse as `(row, column, bit)`.
+    pub fn set_bits(&self) -> impl Iterator<Item = (usize, usize, u8)> + '_ {
+        let columns = self.device.bytes_per_row() - 1;
+        (0..self.device.rows()).flat_map(move |row| {
+            (0..columns).flat_map(move |column| {
+                (0..8u8).filter(move |&bit| self.fabric.get(row, column, bit)).map(move |bit| (row, column, bit))
+            })
+        })
+    }
+
+    /// Export this bitstream as a line-oriented fuse-map: a device header
+    /// line (`idcode:XXXXXXXX`) followed by one `row:col:bit` line per set
+    /// fuse, suitable for diffing or hand-editing.
+    pub fn to_fuse_map<W: Write>(&self, mut out: W) -> io::Result<()> {
+        writeln!(out, "idcode:{:08x}", self.device.jtag_id())?;
+
+        for (row, column, bit) in self.set_bits() {
+            writeln!(out, "{row}:{column}:{bit}")?;
+        }
+
+        Ok(())
+    }
+
+    /// Re-assemble a bitstream from a fuse-map produced by [`Self::to_fuse_map`].
+    pub fn from_fuse_map<R: Read>(input: R) -> Result<Self, BitstreamError> {
+        let mut lines = io::BufRead::lines(io::BufReader::new(input));
+
+        let header = lines.next().ok_or(BitstreamError::Truncated)??;
+        let idcode_str = header.strip_prefix("idcode:").ok_or_else(|| BitstreamError::MalformedFuseMap(header.clone()))?;
+        let idcode = u32::from_str_radix(idcode_str, 16).map_err(|_| BitstreamError::MalformedFuseMap(header.clone()))?;
+        let device = Device::try_from_jtag(idcode).ok_or(BitstreamError::UnknownIdcode(idcode))?;
+
+        let mut fabric = Fabric::new(device);
+
+        for line in lines {
+            let line = line?;
+
+            let mut parts = line.splitn(3, ':');
+            let (row, column, bit) = (|| {
+                let row = parts.next()?.parse::<usize>().ok()?;
+                let column = parts.next()?.parse::<usize>().ok()?;
+                let bit = parts.next()?.parse::<u8>().ok()?;
+                Some((row, column, bit))
+            })().ok_or_else(|| BitstreamError::MalformedFuseMap(line.clone()))?;
+
+            if row >= device.rows() || column >= device.bytes_per_row() - 1 || bit >= 8 {
+                return Err(BitstreamError::MalformedFuseMap(line));
+            }
+
+            fabric.set(row, column, bit, true);
+        }
+
+        Ok(Self {
+            device,
+            data_type: 0,
+            fabric,
         })
     }
 }
 
 #[cfg(test)]
 mod tests {
-    use crate::{ecb_calc, Bitstream};
+    use crate::{ecb_calc, Bitstream, BitstreamError, CodecRegistry, CompressedCodec, EncryptedCodec, TestDataCodec};
 
     #[test]
     fn ecb_calc_is_correct() {
@@ -125,4 +417,114 @@ mod tests {
         let bytes = include_bytes!("nor_mpa1100.bit");
         let _ = Bitstream::new(bytes.as_slice()).unwrap();
     }
+
+    #[test]
+    fn round_trip_mpa1036_bitstream() {
+        let bytes = include_bytes!("and_mpa1036.bit");
+        let bitstream = Bitstream::new(bytes.as_slice()).unwrap();
+
+        let mut written = Vec::new();
+        bitstream.write(&mut written).unwrap();
+        assert_eq!(written, bytes);
+    }
+
+    #[test]
+    fn round_trip_mpa1100_bitstream() {
+        let bytes = include_bytes!("nor_mpa1100.bit");
+        let bitstream = Bitstream::new(bytes.as_slice()).unwrap();
+
+        let mut written = Vec::new();
+        bitstream.write(&mut written).unwrap();
+        assert_eq!(written, bytes);
+    }
+
+    #[test]
+    fn round_trip_mpa1036_fuse_map() {
+        let bytes = include_bytes!("and_mpa1036.bit");
+        let bitstream = Bitstream::new(bytes.as_slice()).unwrap();
+
+        let mut fuse_map = Vec::new();
+        bitstream.to_fuse_map(&mut fuse_map).unwrap();
+
+        let reassembled = Bitstream::from_fuse_map(fuse_map.as_slice()).unwrap();
+        let mut written = Vec::new();
+        reassembled.write(&mut written).unwrap();
+        assert_eq!(written, bytes);
+    }
+
+    #[test]
+    fn round_trip_mpa1100_fuse_map() {
+        let bytes = include_bytes!("nor_mpa1100.bit");
+        let bitstream = Bitstream::new(bytes.as_slice()).unwrap();
+
+        let mut fuse_map = Vec::new();
+        bitstream.to_fuse_map(&mut fuse_map).unwrap();
+
+        let reassembled = Bitstream::from_fuse_map(fuse_map.as_slice()).unwrap();
+        let mut written = Vec::new();
+        reassembled.write(&mut written).unwrap();
+        assert_eq!(written, bytes);
+    }
+
+    #[test]
+    fn from_fuse_map_rejects_out_of_range_row() {
+        let err = Bitstream::from_fuse_map("idcode:1390e01d\n9999:0:0\n".as_bytes()).unwrap_err();
+        assert!(matches!(err, BitstreamError::MalformedFuseMap(_)));
+    }
+
+    #[test]
+    fn from_fuse_map_rejects_out_of_range_bit() {
+        let err = Bitstream::from_fuse_map("idcode:1390e01d\n0:0:200\n".as_bytes()).unwrap_err();
+        assert!(matches!(err, BitstreamError::MalformedFuseMap(_)));
+    }
+
+    #[test]
+    fn reserved_data_type_bits_are_rejected() {
+        let bytes = include_bytes!("and_mpa1036.bit");
+        let mut with_reserved_bit = bytes.to_vec();
+        with_reserved_bit[4] |= 0x08;
+
+        let err = Bitstream::new(with_reserved_bit.as_slice()).unwrap_err();
+        assert!(matches!(err, BitstreamError::ReservedBitsSet(0x08)));
+    }
+
+    #[test]
+    fn compressed_mode_without_codec_is_unsupported() {
+        let bytes = include_bytes!("and_mpa1036.bit");
+        let mut compressed = bytes.to_vec();
+        compressed[4] |= 0x4;
+
+        let err = Bitstream::new(compressed.as_slice()).unwrap_err();
+        assert!(matches!(err, BitstreamError::UnsupportedMode { compressed: true, .. }));
+    }
+
+    #[test]
+    fn compressed_mode_with_registered_codec_decodes() {
+        let bytes = include_bytes!("and_mpa1036.bit");
+        let mut compressed = bytes.to_vec();
+        compressed[4] |= 0x4;
+
+        let registry = CodecRegistry::default().with_compressed_codec(Box::new(CompressedCodec));
+        let _ = Bitstream::new_with_codecs(compressed.as_slice(), registry).unwrap();
+    }
+
+    #[test]
+    fn test_data_mode_with_registered_codec_decodes() {
+        let bytes = include_bytes!("and_mpa1036.bit");
+        let mut test_data = bytes.to_vec();
+        test_data[4] |= 0x1;
+
+        let registry = CodecRegistry::default().with_test_codec(Box::new(TestDataCodec));
+        let _ = Bitstream::new_with_codecs(test_data.as_slice(), registry).unwrap();
+    }
+
+    #[test]
+    fn encrypted_mode_with_registered_codec_decodes() {
+        let bytes = include_bytes!("and_mpa1036.bit");
+        let mut encrypted = bytes.to_vec();
+        encrypted[4] |= 0x2;
+
+        let registry = CodecRegistry::default().with_encrypted_codec(Box::new(EncryptedCodec));
+        let _ = Bitstream::new_with_codecs(encrypted.as_slice(), registry).unwrap();
+    }
 }
@@ -0,0 +1,303 @@
+//! Driving the JTAG TAP state machine to load a [`Bitstream`] onto an MPA
+//! device. The core logic is transport-agnostic: implement [`Transport`] for
+//! a serial link, an FTDI MPSSE adapter, or anything else that can shift
+//! bits through IR and DR, and [`Programmer`] takes care of the rest.
+
+use std::collections::VecDeque;
+use std::fmt;
+
+use crate::{ecb_calc, Bitstream, Device};
+
+/// TAP instruction register opcodes for MPA devices.
+const IR_IDCODE: u8 = 0x01;
+const IR_CONFIGURE: u8 = 0x02;
+
+/// A JTAG transport capable of resetting the TAP and shifting bits through
+/// the instruction and data registers.
+pub trait Transport {
+    type Error: std::error::Error;
+
+    /// Reset the TAP state machine to Test-Logic-Reset.
+    fn reset(&mut self) -> Result<(), Self::Error>;
+
+    /// Shift `bits` into the instruction register, returning the bits
+    /// captured from TDO.
+    fn shift_ir(&mut self, bits: &[u8]) -> Result<Vec<u8>, Self::Error>;
+
+    /// Shift `bits` into the data register, returning the bits captured
+    /// from TDO.
+    fn shift_dr(&mut self, bits: &[u8]) -> Result<Vec<u8>, Self::Error>;
+}
+
+/// Everything that can go wrong while programming a device.
+#[derive(Debug)]
+pub enum ProgramError<E> {
+    /// The transport itself failed.
+    Transport(E),
+    /// The IDCODE read back from the device didn't match the bitstream's target.
+    IdcodeMismatch { expected: u32, got: u32 },
+}
+
+impl<E: fmt::Display> fmt::Display for ProgramError<E> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Transport(err) => write!(f, "transport error: {err}"),
+            Self::IdcodeMismatch { expected, got } => {
+                write!(f, "IDCODE mismatch: expected {expected:08x}, got {got:08x}")
+            }
+        }
+    }
+}
+
+impl<E: std::error::Error + 'static> std::error::Error for ProgramError<E> {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Self::Transport(err) => Some(err),
+            Self::IdcodeMismatch { .. } => None,
+        }
+    }
+}
+
+/// Whether an in-progress [`Programmer::poll`] loop has more work to do.
+#[derive(Debug, PartialEq, Eq)]
+pub enum ProgramProgress {
+    Pending,
+    Complete,
+}
+
+enum ProgramState {
+    Idle,
+    CheckIdcode { device: Device, rows: VecDeque<Vec<u8>> },
+    ShiftRows { rows: VecDeque<Vec<u8>> },
+}
+
+/// Drives the TAP state machine over a [`Transport`] to program a [`Bitstream`].
+pub struct Programmer<T: Transport> {
+    transport: T,
+    state: ProgramState,
+}
+
+impl<T: Transport> Programmer<T> {
+    pub fn new(transport: T) -> Self {
+        Self { transport, state: ProgramState::Idle }
+    }
+
+    /// Program `bs` onto the device, blocking until the whole bitstream has
+    /// been shifted in.
+    pub fn program_blocking(&mut self, bs: &Bitstream) -> Result<(), ProgramError<T::Error>> {
+        self.enqueue(bs);
+        while self.poll()? == ProgramProgress::Pending {}
+        Ok(())
+    }
+
+    /// Queue `bs` for programming. Call [`Self::poll`] repeatedly to drive
+    /// it forward one transport transfer at a time.
+    pub fn enqueue(&mut self, bs: &Bitstream) {
+        let rows = bs.fabric.rows.iter().map(|row| {
+            let mut framed = row.clone();
+            framed.push(ecb_calc(row));
+            framed
+        }).collect();
+
+        self.state = ProgramState::CheckIdcode { device: bs.device, rows };
+    }
+
+    /// Advance the queued program operation by a single transport transfer.
+    ///
+    /// On a transport error, the in-progress step (IDCODE check or the row
+    /// that failed to shift) is kept queued rather than discarded, so a
+    /// failed `poll` never masquerades as [`ProgramProgress::Complete`] and
+    /// a subsequent call can retry from where it left off.
+    pub fn poll(&mut self) -> Result<ProgramProgress, ProgramError<T::Error>> {
+        match std::mem::replace(&mut self.state, ProgramState::Idle) {
+            ProgramState::Idle => Ok(ProgramProgress::Complete),
+
+            ProgramState::CheckIdcode { device, rows } => {
+                let result = (|| {
+                    self.transport.reset().map_err(ProgramError::Transport)?;
+
+                    let response = self.transport.shift_ir(&[IR_IDCODE]).map_err(ProgramError::Transport)?;
+                    let got = read_be_u32(&response);
+                    if Device::try_from_jtag(got).map(|d| d.jtag_id()) != Some(device.jtag_id()) {
+                        return Err(ProgramError::IdcodeMismatch { expected: device.jtag_id(), got });
+                    }
+
+                    self.transport.shift_ir(&[IR_CONFIGURE]).map_err(ProgramError::Transport)?;
+                    Ok(())
+                })();
+
+                match result {
+                    Ok(()) => {
+                        self.state = ProgramState::ShiftRows { rows };
+                        Ok(ProgramProgress::Pending)
+                    }
+                    Err(err) => {
+                        self.state = ProgramState::CheckIdcode { device, rows };
+                        Err(err)
+                    }
+                }
+            }
+
+            ProgramState::ShiftRows { mut rows } => match rows.pop_front() {
+                Some(row) => match self.transport.shift_dr(&row).map_err(ProgramError::Transport) {
+                    Ok(_) => {
+                        self.state = ProgramState::ShiftRows { rows };
+                        Ok(ProgramProgress::Pending)
+                    }
+                    Err(err) => {
+                        rows.push_front(row);
+                        self.state = ProgramState::ShiftRows { rows };
+                        Err(err)
+                    }
+                },
+                None => Ok(ProgramProgress::Complete),
+            },
+        }
+    }
+}
+
+fn read_be_u32(bytes: &[u8]) -> u32 {
+    let mut buf = [0; 4];
+    let len = bytes.len().min(4);
+    buf[4 - len..].copy_from_slice(&bytes[bytes.len() - len..]);
+    u32::from_be_bytes(buf)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    use super::*;
+
+    #[derive(Debug)]
+    struct MockError(&'static str);
+
+    impl fmt::Display for MockError {
+        fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+            write!(f, "{}", self.0)
+        }
+    }
+
+    impl std::error::Error for MockError {}
+
+    /// A fake [`Transport`] that answers IDCODE reads with a fixed value and
+    /// can be told to fail on the Nth `shift_dr` call, to exercise the
+    /// mid-stream error path.
+    struct MockTransport {
+        idcode: u32,
+        fail_after: Option<usize>,
+        call_count: usize,
+        received_rows: Rc<RefCell<Vec<Vec<u8>>>>,
+    }
+
+    impl Transport for MockTransport {
+        type Error = MockError;
+
+        fn reset(&mut self) -> Result<(), Self::Error> {
+            Ok(())
+        }
+
+        fn shift_ir(&mut self, bits: &[u8]) -> Result<Vec<u8>, Self::Error> {
+            if bits == [IR_IDCODE] {
+                Ok(self.idcode.to_be_bytes().to_vec())
+            } else {
+                Ok(Vec::new())
+            }
+        }
+
+        fn shift_dr(&mut self, bits: &[u8]) -> Result<Vec<u8>, Self::Error> {
+            self.call_count += 1;
+            if Some(self.call_count) == self.fail_after {
+                return Err(MockError("transport glitch"));
+            }
+            self.received_rows.borrow_mut().push(bits.to_vec());
+            Ok(Vec::new())
+        }
+    }
+
+    /// Build valid `.bit` bytes for `device` without depending on the
+    /// on-disk fixtures used elsewhere in the crate.
+    fn bitstream_bytes(device: Device) -> Vec<u8> {
+        let mut out = Vec::new();
+        out.extend_from_slice(&device.jtag_id().to_be_bytes());
+        out.push(0);
+
+        for _ in 0..device.rows() {
+            let row = vec![0u8; device.bytes_per_row() - 1];
+            out.extend_from_slice(&row);
+            out.push(ecb_calc(&row));
+        }
+
+        out
+    }
+
+    #[test]
+    fn program_blocking_happy_path() {
+        let device = Device::Mpa1016;
+        let bs = Bitstream::new(bitstream_bytes(device).as_slice()).unwrap();
+
+        let transport = MockTransport {
+            idcode: device.jtag_id(),
+            fail_after: None,
+            call_count: 0,
+            received_rows: Rc::new(RefCell::new(Vec::new())),
+        };
+
+        Programmer::new(transport).program_blocking(&bs).unwrap();
+    }
+
+    #[test]
+    fn program_blocking_reports_idcode_mismatch() {
+        let device = Device::Mpa1016;
+        let bs = Bitstream::new(bitstream_bytes(device).as_slice()).unwrap();
+
+        let transport = MockTransport {
+            idcode: Device::Mpa1036.jtag_id(),
+            fail_after: None,
+            call_count: 0,
+            received_rows: Rc::new(RefCell::new(Vec::new())),
+        };
+
+        let err = Programmer::new(transport).program_blocking(&bs).unwrap_err();
+        assert!(matches!(err, ProgramError::IdcodeMismatch { .. }));
+    }
+
+    #[test]
+    fn mid_stream_transport_error_does_not_drop_the_failed_row() {
+        let device = Device::Mpa1016;
+        let bs = Bitstream::new(bitstream_bytes(device).as_slice()).unwrap();
+
+        let received_rows = Rc::new(RefCell::new(Vec::new()));
+        let transport = MockTransport {
+            idcode: device.jtag_id(),
+            fail_after: Some(2),
+            call_count: 0,
+            received_rows: Rc::clone(&received_rows),
+        };
+
+        let mut programmer = Programmer::new(transport);
+        programmer.enqueue(&bs);
+
+        let mut saw_error = false;
+        loop {
+            match programmer.poll() {
+                Ok(ProgramProgress::Pending) => continue,
+                Ok(ProgramProgress::Complete) => break,
+                Err(ProgramError::Transport(_)) => saw_error = true,
+                Err(err) => panic!("unexpected error: {err}"),
+            }
+        }
+
+        assert!(saw_error, "expected the mocked transport failure to surface via poll()");
+
+        // Despite the glitch, every row was eventually shifted exactly
+        // once — the failed row was retried rather than silently dropped.
+        let expected_rows: Vec<Vec<u8>> = bs.fabric.rows.iter().map(|row| {
+            let mut framed = row.clone();
+            framed.push(ecb_calc(row));
+            framed
+        }).collect();
+        assert_eq!(*received_rows.borrow(), expected_rows);
+    }
+}